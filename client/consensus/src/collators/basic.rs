@@ -24,7 +24,7 @@ use cumulus_primitives_core::{
     PersistedValidationData,
 };
 use cumulus_relay_chain_interface::RelayChainInterface;
-use parity_scale_codec::{Codec, Decode};
+use parity_scale_codec::{Codec, Decode, Encode};
 
 use polkadot_node_primitives::CollationResult;
 use polkadot_overseer::Handle as OverseerHandle;
@@ -43,11 +43,37 @@ use sp_core::crypto::Pair;
 use sp_inherents::CreateInherentDataProviders;
 use sp_keystore::KeystorePtr;
 use sp_runtime::traits::{Block as BlockT, Header as HeaderT, Member};
-use std::{convert::TryFrom, sync::Arc, time::Duration};
+use std::{
+    convert::TryFrom,
+    marker::PhantomData,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::collators as collator_util;
+use crate::collators::metrics::{Metrics, RejectReason};
 use crate::{consensus_orchestrator::RetrieveAuthoritiesFromOrchestrator, AuthorityId};
 
+/// Carries the authority identity and signature scheme for a collator
+/// instantiation.
+///
+/// `run` is generic over this trait rather than directly over a [`Pair`], so
+/// that the same orchestrator-driven collator can be instantiated for
+/// sr25519 Aura authorities, or a different key scheme entirely, without
+/// duplicating `run`.
+pub trait CollatorAuthorityId: Send + Sync + 'static {
+    /// The keypair type backing this authority identity.
+    type Pair: Pair;
+}
+
+/// The Aura authority identity backed by the keypair type `P` (for example
+/// sr25519).
+pub struct AuraAuthorityId<P>(PhantomData<P>);
+
+impl<P: Pair + Send + Sync + 'static> CollatorAuthorityId for AuraAuthorityId<P> {
+    type Pair = P;
+}
+
 /// Parameters for [`run`].
 pub struct Params<BI, CIDP, Client, RClient, SO, Proposer, CS, GOH> {
     pub create_inherent_data_providers: CIDP,
@@ -67,10 +93,23 @@ pub struct Params<BI, CIDP, Client, RClient, SO, Proposer, CS, GOH> {
     pub authoring_duration: Duration,
     pub force_authoring: bool,
     pub collation_request_receiver: Option<Receiver<CollationRequest>>,
+    /// Optional Prometheus metrics for the collation loop. When `None`, no
+    /// metrics are recorded.
+    pub metrics: Option<Metrics>,
+    /// The block size limit to pass to the proposer, in bytes. Defaults to
+    /// 50% of `validation_data.max_pov_size` when `None`, which is the
+    /// right choice until the runtime's weights account for proof size.
+    /// Parachains that have migrated to proof-size-aware weights can raise
+    /// this toward the full `max_pov_size`.
+    pub block_size_limit: Option<usize>,
+    /// The maximum number of blocks to author for a single `CollationRequest`
+    /// when this collator holds consecutive Aura slots. Defaults to 1 when
+    /// `None`, i.e. the pre-async-backing behavior of one block per request.
+    pub max_blocks_per_request: Option<usize>,
 }
 
 /// Run tanssi Aura consensus as a relay-chain-driven collator.
-pub fn run<Block, P, BI, CIDP, Client, RClient, SO, Proposer, CS, GOH>(
+pub fn run<Block, AI, BI, CIDP, Client, RClient, SO, Proposer, CS, GOH>(
     params: Params<BI, CIDP, Client, RClient, SO, Proposer, CS, GOH>,
 ) -> impl Future<Output = ()> + Send + 'static
 where
@@ -93,13 +132,13 @@ where
     SO: SyncOracle + Send + Sync + Clone + 'static,
     Proposer: ProposerInterface<Block> + Send + Sync + 'static,
     CS: CollatorServiceInterface<Block> + Send + Sync + 'static,
-    P: Pair,
-    P::Public: AppPublic + Member + Codec,
-    P::Signature: TryFrom<Vec<u8>> + Member + Codec,
+    AI: CollatorAuthorityId,
+    <AI::Pair as Pair>::Public: AppPublic + Member + Codec,
+    <AI::Pair as Pair>::Signature: TryFrom<Vec<u8>> + Member + Codec,
     GOH: RetrieveAuthoritiesFromOrchestrator<
             Block,
             (PHash, PersistedValidationData),
-            Vec<AuthorityId<P>>,
+            Vec<AuthorityId<AI::Pair>>,
         >
         + 'static
         + Sync
@@ -129,10 +168,16 @@ where
                 collator_service: params.collator_service,
             };
 
-            collator_util::Collator::<Block, P, _, _, _, _, _>::new(params)
+            collator_util::Collator::<Block, AI::Pair, _, _, _, _, _>::new(params)
         };
 
+        let metrics = params.metrics.clone();
+
         while let Some(request) = collation_requests.next().await {
+            if let Some(metrics) = &metrics {
+                metrics.on_collation_requested();
+            }
+
             macro_rules! reject_with_error {
 				($err:expr) => {{
 					request.complete(None);
@@ -163,6 +208,9 @@ where
                 .collator_service()
                 .check_block_status(parent_hash, &parent_header)
             {
+                if let Some(metrics) = &metrics {
+                    metrics.on_collation_rejected(RejectReason::BadBlockStatus);
+                }
                 continue;
             }
 
@@ -201,46 +249,163 @@ where
                 Ok(h) => h,
             };
 
-            let mut claim = match collator_util::tanssi_claim_slot::<P>(
-                authorities,
+            let mut claim = match collator_util::tanssi_claim_slot::<AI::Pair>(
+                authorities.clone(),
                 inherent_providers.slot(),
                 params.force_authoring,
                 &params.keystore,
             ) {
-                Ok(None) => continue,
+                Ok(None) => {
+                    if let Some(metrics) = &metrics {
+                        metrics.on_collation_rejected(RejectReason::NotOurSlot);
+                    }
+                    continue;
+                }
                 Err(e) => reject_with_error!(e),
                 Ok(Some(h)) => h,
             };
 
-            let (parachain_inherent_data, other_inherent_data) = try_request!(
-                collator
+            // Default to 50% of the maximum PoV size until the runtime's
+            // weights account for proof size; operators can raise this via
+            // `Params::block_size_limit` once they have proof-size-aware
+            // weights.
+            let block_size_limit = params
+                .block_size_limit
+                .unwrap_or((validation_data.max_pov_size / 2) as usize);
+            let max_blocks_per_request = params.max_blocks_per_request.unwrap_or(1).max(1);
+
+            let mut building_parent_header = parent_header;
+            let mut building_parent_hash = parent_hash;
+            let mut next_slot = inherent_providers.slot();
+            let mut remaining_pov_size = block_size_limit;
+            let mut remaining_authoring_duration = params.authoring_duration;
+
+            let mut last_collation = None;
+            let mut last_post_hash = building_parent_hash;
+
+            for block_index in 0..max_blocks_per_request {
+                let (parachain_inherent_data, other_inherent_data) = match collator
                     .create_inherent_data(
                         *request.relay_parent(),
                         &validation_data,
-                        parent_hash,
+                        building_parent_hash,
                         None,
                     )
                     .await
-            );
+                {
+                    Ok(x) => x,
+                    Err(e) => {
+                        // A failure on the first block leaves `last_collation`
+                        // unset, which rejects the request after the loop.
+                        if block_index == 0 {
+                            tracing::error!(target: crate::LOG_TARGET, err = ?e);
+                        } else {
+                            tracing::error!(target: crate::LOG_TARGET, err = ?e, "failed to build inherent data for additional block in unincluded segment");
+                        }
+                        break;
+                    }
+                };
 
-            let (collation, _, post_hash) = try_request!(
-                collator
+                let authoring_started = Instant::now();
+
+                let (collation, _, post_hash) = match collator
                     .collate(
-                        &parent_header,
+                        &building_parent_header,
                         &mut claim,
                         None,
                         (parachain_inherent_data, other_inherent_data),
-                        params.authoring_duration,
-                        // Set the block limit to 50% of the maximum PoV size.
-                        //
-                        // TODO: If we got benchmarking that includes the proof size,
-                        // we should be able to use the maximum pov size.
-                        (validation_data.max_pov_size / 2) as usize,
+                        remaining_authoring_duration,
+                        remaining_pov_size,
                     )
                     .await
-            );
+                {
+                    Ok(x) => x,
+                    Err(e) => {
+                        // Only count this as a rejected collation request if
+                        // we failed to produce even the first block; later
+                        // failures just truncate an otherwise-successful
+                        // chained request, which is still submitted below.
+                        if block_index == 0 {
+                            if let Some(metrics) = &metrics {
+                                metrics.on_collation_rejected(RejectReason::ProposerError);
+                            }
+                            tracing::error!(target: crate::LOG_TARGET, err = ?e);
+                        } else {
+                            tracing::error!(target: crate::LOG_TARGET, err = ?e, "failed to author additional block in unincluded segment");
+                        }
+                        break;
+                    }
+                };
+
+                let authoring_elapsed = authoring_started.elapsed();
+                let pov_size = collation.proof_of_validity.encode().len();
+
+                if let Some(metrics) = &metrics {
+                    metrics.on_collation_produced(
+                        authoring_elapsed.as_secs_f64(),
+                        pov_size as f64 / validation_data.max_pov_size as f64,
+                    );
+                }
 
-            let result_sender = Some(collator.collator_service().announce_with_barrier(post_hash));
+                last_collation = Some(collation);
+                last_post_hash = post_hash;
+
+                let more_blocks_wanted = block_index + 1 < max_blocks_per_request;
+                if !more_blocks_wanted {
+                    break;
+                }
+
+                remaining_pov_size = remaining_pov_size.saturating_sub(pov_size);
+                remaining_authoring_duration =
+                    remaining_authoring_duration.saturating_sub(authoring_elapsed);
+                if remaining_pov_size == 0 || remaining_authoring_duration.is_zero() {
+                    break;
+                }
+
+                next_slot = (*next_slot + 1).into();
+                claim = match collator_util::tanssi_claim_slot::<AI::Pair>(
+                    authorities.clone(),
+                    next_slot,
+                    params.force_authoring,
+                    &params.keystore,
+                ) {
+                    Ok(Some(claim)) => claim,
+                    // We no longer hold consecutive slots, or failed to
+                    // claim the next one: stop chaining and submit what we
+                    // have so far.
+                    Ok(None) => break,
+                    Err(e) => {
+                        tracing::error!(target: crate::LOG_TARGET, err = ?e, "failed to claim consecutive slot");
+                        break;
+                    }
+                };
+
+                building_parent_header = match Block::Header::decode(
+                    &mut &last_collation.as_ref().expect("set above; qed").head_data.0[..],
+                ) {
+                    Ok(h) => h,
+                    Err(e) => {
+                        tracing::error!(target: crate::LOG_TARGET, err = ?e, "failed to decode authored header");
+                        break;
+                    }
+                };
+                building_parent_hash = post_hash;
+            }
+
+            let collation = match last_collation {
+                Some(collation) => collation,
+                // Not even the first block was authored: reject the request.
+                None => {
+                    request.complete(None);
+                    continue;
+                }
+            };
+
+            let result_sender = Some(
+                collator
+                    .collator_service()
+                    .announce_with_barrier(last_post_hash),
+            );
             request.complete(Some(CollationResult {
                 collation,
                 result_sender,
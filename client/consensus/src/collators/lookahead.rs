@@ -0,0 +1,1141 @@
+// Copyright (C) Moondance Labs Ltd.
+// This file is part of Tanssi.
+
+// Tanssi is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Tanssi is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Tanssi.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A lookahead collator that builds on potential, not-yet-included parachain
+//! parents in order to support async backing.
+//!
+//! Unlike [`super::basic`], which always builds directly on the included
+//! parachain head supplied in `validation_data.parent_head`, this collator
+//! searches the relay chain for parachain candidates that are backed or
+//! pending availability and may therefore become valid parents before the
+//! relay chain includes them. This lets the collator author several
+//! parachain blocks within a single relay chain slot.
+
+use cumulus_client_collator::{
+    relay_chain_driven::CollationRequest, service::ServiceInterface as CollatorServiceInterface,
+};
+use cumulus_client_consensus_common::ParachainBlockImportMarker;
+use cumulus_client_consensus_proposer::ProposerInterface;
+use cumulus_primitives_core::{
+    relay_chain::{BlockId as RBlockId, BlockNumber as RBlockNumber, Hash as PHash},
+    PersistedValidationData,
+};
+use cumulus_relay_chain_interface::RelayChainInterface;
+use parity_scale_codec::{Codec, Decode, Encode};
+
+use polkadot_node_primitives::CollationResult;
+use polkadot_node_subsystem::messages::{CollationGenerationMessage, SubmitCollationParams};
+use polkadot_overseer::Handle as OverseerHandle;
+use polkadot_primitives::{CollatorPair, Id as ParaId};
+
+use futures::{
+    channel::mpsc::{Receiver, Sender},
+    prelude::*,
+};
+use sc_client_api::{backend::AuxStore, BlockBackend, BlockOf};
+use sc_consensus::BlockImport;
+use sc_consensus_slots::{time_until_next_slot, InherentDataProviderExt};
+use sp_api::ProvideRuntimeApi;
+use sp_application_crypto::AppPublic;
+use sp_blockchain::HeaderBackend;
+use sp_consensus::SyncOracle;
+use sp_consensus_aura::SlotDuration;
+use sp_core::crypto::Pair;
+use sp_inherents::CreateInherentDataProviders;
+use sp_keystore::KeystorePtr;
+use sp_runtime::traits::{Block as BlockT, Header as HeaderT, Member};
+use futures_timer::Delay;
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::collators as collator_util;
+use crate::collators::basic::CollatorAuthorityId;
+use crate::collators::metrics::{Metrics, RejectReason};
+use crate::{consensus_orchestrator::RetrieveAuthoritiesFromOrchestrator, AuthorityId};
+
+/// Parameters for [`run`].
+pub struct Params<BI, CIDP, Client, RClient, SO, Proposer, CS, GOH> {
+    pub create_inherent_data_providers: CIDP,
+    pub get_authorities_from_orchestrator: GOH,
+    pub block_import: BI,
+    pub para_client: Arc<Client>,
+    pub relay_client: RClient,
+    pub sync_oracle: SO,
+    pub keystore: KeystorePtr,
+    pub collator_key: CollatorPair,
+    pub para_id: ParaId,
+    pub overseer_handle: OverseerHandle,
+    pub slot_duration: SlotDuration,
+    pub relay_chain_slot_duration: Duration,
+    pub proposer: Proposer,
+    pub collator_service: CS,
+    pub authoring_duration: Duration,
+    pub force_authoring: bool,
+    pub collation_request_receiver: Option<Receiver<CollationRequest>>,
+    /// How many relay chain blocks behind the current best we are willing to
+    /// look when searching for potential parents.
+    pub ancestry_lookback: usize,
+    /// The maximum length of the unincluded parachain segment we are willing
+    /// to build on top of.
+    pub max_depth: usize,
+    /// Optional Prometheus metrics for the collation loop. When `None`, no
+    /// metrics are recorded.
+    pub metrics: Option<Metrics>,
+    /// The block size limit to pass to the proposer, in bytes. Defaults to
+    /// 50% of `validation_data.max_pov_size` when `None`, which is the
+    /// right choice until the runtime's weights account for proof size.
+    /// Parachains that have migrated to proof-size-aware weights can raise
+    /// this toward the full `max_pov_size`.
+    pub block_size_limit: Option<usize>,
+    /// The maximum number of blocks to author for a single `CollationRequest`
+    /// when this collator holds consecutive Aura slots. Defaults to 1 when
+    /// `None`, i.e. the pre-async-backing behavior of one block per request.
+    pub max_blocks_per_request: Option<usize>,
+}
+
+/// A parachain block that may be built upon, discovered while walking relay
+/// chain ancestors.
+#[derive(Debug, Clone)]
+struct PotentialParent<Block: BlockT> {
+    header: Block::Header,
+    depth: usize,
+    relay_parent_number: RBlockNumber,
+}
+
+/// Search the relay chain ancestry of `relay_parent`, starting from the
+/// parachain block currently pending availability, for parachain candidates
+/// that are backed or pending availability and could become valid parents to
+/// build upon.
+///
+/// Returns the deepest valid potential parent, falling back to the included
+/// block if no candidates were found. A node is a valid build target only if
+/// its `depth < max_depth` and its relay parent is not older than
+/// `relay_parent_number - ancestry_lookback`.
+async fn find_potential_parent<Block, RClient>(
+    relay_client: &RClient,
+    relay_parent: PHash,
+    para_id: ParaId,
+    ancestry_lookback: usize,
+    max_depth: usize,
+) -> Result<Option<PotentialParent<Block>>, Box<dyn std::error::Error + Send + Sync>>
+where
+    Block: BlockT,
+    RClient: RelayChainInterface + Clone,
+{
+    let included = match relay_client
+        .persisted_validation_data(relay_parent, para_id, Default::default())
+        .await?
+    {
+        Some(pvd) => pvd,
+        None => return Ok(None),
+    };
+    let included_header = Block::Header::decode(&mut &included.parent_head.0[..])?;
+    let included_hash = included_header.hash();
+
+    let relay_parent_number = match relay_client.header(RBlockId::hash(relay_parent)).await? {
+        Some(h) => *h.number(),
+        None => return Ok(None),
+    };
+    let min_relay_parent_number = relay_parent_number.saturating_sub(ancestry_lookback as u32);
+
+    // Collect every backed/pending-availability candidate found within the
+    // ancestry window, keyed by its own hash. We don't yet know which of
+    // these actually chain from the included block; that's resolved below.
+    let mut candidates: HashMap<Block::Hash, (Block::Header, RBlockNumber)> = HashMap::new();
+
+    let mut seen_relay_blocks = HashSet::new();
+    let mut frontier = vec![relay_parent];
+
+    // `relay_parent` itself is processed in the first iteration, so we need
+    // `ancestry_lookback + 1` iterations to actually visit `ancestry_lookback`
+    // ancestors beyond it, down to and including `min_relay_parent_number`.
+    for _ in 0..=ancestry_lookback {
+        let mut next_frontier = Vec::new();
+
+        for block in frontier {
+            if !seen_relay_blocks.insert(block) {
+                continue;
+            }
+
+            let header = match relay_client.header(RBlockId::hash(block)).await? {
+                Some(h) => h,
+                None => continue,
+            };
+            let number = *header.number();
+            if number < min_relay_parent_number {
+                continue;
+            }
+
+            let found = relay_client
+                .candidates_pending_availability(block, para_id)
+                .await?
+                .into_iter()
+                .chain(relay_client.backed_candidates(block, para_id).await?);
+
+            for candidate in found {
+                let candidate_header = Block::Header::decode(&mut &candidate.0[..])?;
+                let hash = candidate_header.hash();
+                candidates.entry(hash).or_insert((candidate_header, number));
+            }
+
+            next_frontier.push(*header.parent_hash());
+        }
+
+        frontier = next_frontier;
+        if frontier.is_empty() {
+            break;
+        }
+    }
+
+    Ok(Some(resolve_potential_parent::<Block>(
+        included_header,
+        relay_parent_number,
+        candidates,
+        max_depth,
+    )))
+}
+
+/// Given the included parachain block and every backed/pending-availability
+/// candidate discovered in the ancestry window (keyed by the candidate's own
+/// hash), resolve each candidate's depth by following actual parent-hash
+/// linkage back to the included block, and return the deepest valid
+/// potential parent.
+///
+/// Candidates can be discovered in any order, so this repeatedly tries to
+/// link unresolved candidates onto nodes whose depth has already been
+/// established, until no more progress can be made. A candidate whose parent
+/// is never found among `candidates` or at the included block is not part of
+/// the unincluded segment and is never assigned a depth, so it can never
+/// become a build target, even if another candidate happens to be found at
+/// the same relay block.
+fn resolve_potential_parent<Block: BlockT>(
+    included_header: Block::Header,
+    included_relay_parent_number: RBlockNumber,
+    candidates: HashMap<Block::Hash, (Block::Header, RBlockNumber)>,
+    max_depth: usize,
+) -> PotentialParent<Block> {
+    let included_hash = included_header.hash();
+
+    let mut depths: HashMap<Block::Hash, usize> = HashMap::new();
+    depths.insert(included_hash, 0);
+
+    // Root of the tree: the included block, at depth 0.
+    let mut best: PotentialParent<Block> = PotentialParent {
+        header: included_header,
+        depth: 0,
+        relay_parent_number: included_relay_parent_number,
+    };
+
+    loop {
+        let mut progressed = false;
+
+        for (hash, (header, number)) in &candidates {
+            if depths.contains_key(hash) {
+                continue;
+            }
+
+            let Some(&parent_depth) = depths.get(header.parent_hash()) else {
+                continue;
+            };
+
+            let depth = parent_depth + 1;
+            depths.insert(*hash, depth);
+            progressed = true;
+
+            if depth < max_depth && depth > best.depth {
+                best = PotentialParent {
+                    header: header.clone(),
+                    depth,
+                    relay_parent_number: *number,
+                };
+            }
+        }
+
+        if !progressed {
+            break;
+        }
+    }
+
+    best
+}
+
+/// Run tanssi Aura consensus as a lookahead, async-backing-aware collator.
+///
+/// Builds on the deepest valid potential parent found via [`find_potential_parent`]
+/// rather than always trusting the included parachain head.
+pub fn run<Block, AI, BI, CIDP, Client, RClient, SO, Proposer, CS, GOH>(
+    params: Params<BI, CIDP, Client, RClient, SO, Proposer, CS, GOH>,
+) -> impl Future<Output = ()> + Send + 'static
+where
+    Block: BlockT + Send,
+    Client: ProvideRuntimeApi<Block>
+        + BlockOf
+        + AuxStore
+        + HeaderBackend<Block>
+        + BlockBackend<Block>
+        + Send
+        + Sync
+        + 'static,
+    RClient: RelayChainInterface + Send + Clone + 'static,
+    CIDP: CreateInherentDataProviders<Block, (PHash, PersistedValidationData)>
+        + Send
+        + 'static
+        + Clone,
+    CIDP::InherentDataProviders: Send + InherentDataProviderExt,
+    BI: BlockImport<Block> + ParachainBlockImportMarker + Send + Sync + 'static,
+    SO: SyncOracle + Send + Sync + Clone + 'static,
+    Proposer: ProposerInterface<Block> + Send + Sync + 'static,
+    CS: CollatorServiceInterface<Block> + Send + Sync + 'static,
+    AI: CollatorAuthorityId,
+    <AI::Pair as Pair>::Public: AppPublic + Member + Codec,
+    <AI::Pair as Pair>::Signature: TryFrom<Vec<u8>> + Member + Codec,
+    GOH: RetrieveAuthoritiesFromOrchestrator<
+            Block,
+            (PHash, PersistedValidationData),
+            Vec<AuthorityId<AI::Pair>>,
+        >
+        + 'static
+        + Sync
+        + Send,
+{
+    async move {
+        let mut collation_requests = match params.collation_request_receiver {
+            Some(receiver) => receiver,
+            None => {
+                cumulus_client_collator::relay_chain_driven::init(
+                    params.collator_key,
+                    params.para_id,
+                    params.overseer_handle,
+                )
+                .await
+            }
+        };
+
+        let mut collator = {
+            let params = collator_util::Params {
+                create_inherent_data_providers: params.create_inherent_data_providers.clone(),
+                block_import: params.block_import,
+                relay_client: params.relay_client.clone(),
+                keystore: params.keystore.clone(),
+                para_id: params.para_id,
+                proposer: params.proposer,
+                collator_service: params.collator_service,
+            };
+
+            collator_util::Collator::<Block, AI::Pair, _, _, _, _, _>::new(params)
+        };
+
+        let metrics = params.metrics.clone();
+
+        while let Some(request) = collation_requests.next().await {
+            if let Some(metrics) = &metrics {
+                metrics.on_collation_requested();
+            }
+
+            macro_rules! reject_with_error {
+				($err:expr) => {{
+					request.complete(None);
+					tracing::error!(target: crate::LOG_TARGET, err = ?{ $err });
+					continue;
+				}};
+			}
+
+            let validation_data = request.persisted_validation_data();
+
+            let parent = match find_potential_parent::<Block, _>(
+                &params.relay_client,
+                *request.relay_parent(),
+                params.para_id,
+                params.ancestry_lookback,
+                params.max_depth,
+            )
+            .await
+            {
+                Ok(Some(parent)) => parent,
+                Ok(None) => continue,
+                Err(e) => reject_with_error!(e),
+            };
+
+            let parent_depth = parent.depth;
+            let parent_header = parent.header;
+            let parent_hash = parent_header.hash();
+
+            // Check whether we can build upon this block
+            if !collator
+                .collator_service()
+                .check_block_status(parent_hash, &parent_header)
+            {
+                if let Some(metrics) = &metrics {
+                    metrics.on_collation_rejected(RejectReason::BadBlockStatus);
+                }
+                continue;
+            }
+
+            let relay_parent_header = match params
+                .relay_client
+                .header(RBlockId::hash(*request.relay_parent()))
+                .await
+            {
+                Err(e) => reject_with_error!(e),
+                Ok(None) => continue, // sanity: would be inconsistent to get `None` here
+                Ok(Some(h)) => h,
+            };
+
+            // Retrieve authorities that are able to produce the block
+            let authorities = match params
+                .get_authorities_from_orchestrator
+                .retrieve_authorities_from_orchestrator(
+                    parent_hash,
+                    (relay_parent_header.hash(), validation_data.clone()),
+                )
+                .await
+            {
+                Err(e) => reject_with_error!(e),
+                Ok(h) => h,
+            };
+
+            let inherent_providers = match params
+                .create_inherent_data_providers
+                .create_inherent_data_providers(
+                    parent_hash,
+                    (*request.relay_parent(), validation_data.clone()),
+                )
+                .await
+            {
+                Err(e) => reject_with_error!(e),
+                Ok(h) => h,
+            };
+
+            let mut claim = match collator_util::tanssi_claim_slot::<AI::Pair>(
+                authorities.clone(),
+                inherent_providers.slot(),
+                params.force_authoring,
+                &params.keystore,
+            ) {
+                Ok(None) => {
+                    if let Some(metrics) = &metrics {
+                        metrics.on_collation_rejected(RejectReason::NotOurSlot);
+                    }
+                    continue;
+                }
+                Err(e) => reject_with_error!(e),
+                Ok(Some(h)) => h,
+            };
+
+            // Default to 50% of the maximum PoV size until the runtime's
+            // weights account for proof size; operators can raise this via
+            // `Params::block_size_limit` once they have proof-size-aware
+            // weights.
+            let block_size_limit = params
+                .block_size_limit
+                .unwrap_or((validation_data.max_pov_size / 2) as usize);
+            // `parent.depth` already accounts for `params.max_depth`, so cap the
+            // chain length here to avoid authoring blocks that would push the
+            // unincluded segment past the relay chain's async backing limit.
+            let max_blocks_per_request = params
+                .max_blocks_per_request
+                .unwrap_or(1)
+                .max(1)
+                .min(params.max_depth.saturating_sub(parent_depth).max(1));
+
+            let mut building_parent_header = parent_header;
+            let mut building_parent_hash = parent_hash;
+            let mut next_slot = inherent_providers.slot();
+            let mut remaining_pov_size = block_size_limit;
+            let mut remaining_authoring_duration = params.authoring_duration;
+
+            let mut last_collation = None;
+            let mut last_post_hash = building_parent_hash;
+
+            for block_index in 0..max_blocks_per_request {
+                let (parachain_inherent_data, other_inherent_data) = match collator
+                    .create_inherent_data(
+                        *request.relay_parent(),
+                        &validation_data,
+                        building_parent_hash,
+                        None,
+                    )
+                    .await
+                {
+                    Ok(x) => x,
+                    Err(e) => {
+                        // A failure on the first block leaves `last_collation`
+                        // unset, which rejects the request after the loop.
+                        if block_index == 0 {
+                            tracing::error!(target: crate::LOG_TARGET, err = ?e);
+                        } else {
+                            tracing::error!(target: crate::LOG_TARGET, err = ?e, "failed to build inherent data for additional block in unincluded segment");
+                        }
+                        break;
+                    }
+                };
+
+                let authoring_started = Instant::now();
+
+                let (collation, _, post_hash) = match collator
+                    .collate(
+                        &building_parent_header,
+                        &mut claim,
+                        None,
+                        (parachain_inherent_data, other_inherent_data),
+                        remaining_authoring_duration,
+                        remaining_pov_size,
+                    )
+                    .await
+                {
+                    Ok(x) => x,
+                    Err(e) => {
+                        // Only count this as a rejected collation request if
+                        // we failed to produce even the first block; later
+                        // failures just truncate an otherwise-successful
+                        // chained request, which is still submitted below.
+                        if block_index == 0 {
+                            if let Some(metrics) = &metrics {
+                                metrics.on_collation_rejected(RejectReason::ProposerError);
+                            }
+                            tracing::error!(target: crate::LOG_TARGET, err = ?e);
+                        } else {
+                            tracing::error!(target: crate::LOG_TARGET, err = ?e, "failed to author additional block in unincluded segment");
+                        }
+                        break;
+                    }
+                };
+
+                let authoring_elapsed = authoring_started.elapsed();
+                let pov_size = collation.proof_of_validity.encode().len();
+
+                if let Some(metrics) = &metrics {
+                    metrics.on_collation_produced(
+                        authoring_elapsed.as_secs_f64(),
+                        pov_size as f64 / validation_data.max_pov_size as f64,
+                    );
+                }
+
+                last_collation = Some(collation);
+                last_post_hash = post_hash;
+
+                let more_blocks_wanted = block_index + 1 < max_blocks_per_request;
+                if !more_blocks_wanted {
+                    break;
+                }
+
+                remaining_pov_size = remaining_pov_size.saturating_sub(pov_size);
+                remaining_authoring_duration =
+                    remaining_authoring_duration.saturating_sub(authoring_elapsed);
+                if remaining_pov_size == 0 || remaining_authoring_duration.is_zero() {
+                    break;
+                }
+
+                next_slot = (*next_slot + 1).into();
+                claim = match collator_util::tanssi_claim_slot::<AI::Pair>(
+                    authorities.clone(),
+                    next_slot,
+                    params.force_authoring,
+                    &params.keystore,
+                ) {
+                    Ok(Some(claim)) => claim,
+                    // We no longer hold consecutive slots, or failed to
+                    // claim the next one: stop chaining and submit what we
+                    // have so far.
+                    Ok(None) => break,
+                    Err(e) => {
+                        tracing::error!(target: crate::LOG_TARGET, err = ?e, "failed to claim consecutive slot");
+                        break;
+                    }
+                };
+
+                building_parent_header = match Block::Header::decode(
+                    &mut &last_collation.as_ref().expect("set above; qed").head_data.0[..],
+                ) {
+                    Ok(h) => h,
+                    Err(e) => {
+                        tracing::error!(target: crate::LOG_TARGET, err = ?e, "failed to decode authored header");
+                        break;
+                    }
+                };
+                building_parent_hash = post_hash;
+            }
+
+            let collation = match last_collation {
+                Some(collation) => collation,
+                // Not even the first block was authored: reject the request.
+                None => {
+                    request.complete(None);
+                    continue;
+                }
+            };
+
+            let result_sender = Some(
+                collator
+                    .collator_service()
+                    .announce_with_barrier(last_post_hash),
+            );
+            request.complete(Some(CollationResult {
+                collation,
+                result_sender,
+            }));
+        }
+    }
+}
+
+/// Parameters for [`run_slot_based`].
+///
+/// This mirrors [`Params`] but drops `collation_request_receiver`: block
+/// authoring is no longer driven by the relay chain asking for a collation,
+/// it is driven by the parachain's own slot timer. `select_relay_parent` is
+/// called on every slot to pick the relay parent to anchor the search for a
+/// potential parent on; callers typically supply the relay chain's current
+/// best block.
+pub struct SlotBasedParams<BI, CIDP, Client, RClient, SO, Proposer, CS, GOH, SRP> {
+    pub create_inherent_data_providers: CIDP,
+    pub get_authorities_from_orchestrator: GOH,
+    pub block_import: BI,
+    pub para_client: Arc<Client>,
+    pub relay_client: RClient,
+    pub sync_oracle: SO,
+    pub keystore: KeystorePtr,
+    pub collator_key: CollatorPair,
+    pub para_id: ParaId,
+    pub overseer_handle: OverseerHandle,
+    pub slot_duration: SlotDuration,
+    pub relay_chain_slot_duration: Duration,
+    pub proposer: Proposer,
+    pub collator_service: CS,
+    pub authoring_duration: Duration,
+    pub force_authoring: bool,
+    pub ancestry_lookback: usize,
+    pub max_depth: usize,
+    /// Selects the relay parent to build against on each slot tick.
+    pub select_relay_parent: SRP,
+    /// Optional Prometheus metrics for the collation loop. When `None`, no
+    /// metrics are recorded.
+    pub metrics: Option<Metrics>,
+    /// The block size limit to pass to the proposer, in bytes. Defaults to
+    /// 50% of `validation_data.max_pov_size` when `None`, which is the
+    /// right choice until the runtime's weights account for proof size.
+    /// Parachains that have migrated to proof-size-aware weights can raise
+    /// this toward the full `max_pov_size`.
+    pub block_size_limit: Option<usize>,
+    /// The maximum number of blocks to author per slot tick when this
+    /// collator holds consecutive Aura slots. Defaults to 1 when `None`,
+    /// i.e. one block per tick.
+    pub max_blocks_per_request: Option<usize>,
+}
+
+/// A block produced by the block-building task, handed off to the
+/// collation-submission task.
+struct BuiltCollation<Block: BlockT> {
+    relay_parent: PHash,
+    parent_header: Block::Header,
+    validation_code_hash: cumulus_primitives_core::ValidationCodeHash,
+    result: CollationResult,
+}
+
+/// Run tanssi Aura consensus as a slot-triggered, async-backing-aware
+/// collator.
+///
+/// Unlike [`run`], which waits for the relay chain to request a collation
+/// (anchoring the built block one relay parent later than necessary), this
+/// entry point wakes on each parachain slot boundary and builds directly on
+/// top of the current best relay parent, reducing block latency under async
+/// backing. The work is split into two cooperating tasks connected by an
+/// internal channel: a block-building task that authors blocks on the slot
+/// timer, and a collation-submission task that hands the produced blocks to
+/// the overseer.
+pub fn run_slot_based<Block, AI, BI, CIDP, Client, RClient, SO, Proposer, CS, GOH, SRP, SRPFut>(
+    params: SlotBasedParams<BI, CIDP, Client, RClient, SO, Proposer, CS, GOH, SRP>,
+) -> impl Future<Output = ()> + Send + 'static
+where
+    Block: BlockT + Send,
+    Client: ProvideRuntimeApi<Block>
+        + BlockOf
+        + AuxStore
+        + HeaderBackend<Block>
+        + BlockBackend<Block>
+        + Send
+        + Sync
+        + 'static,
+    RClient: RelayChainInterface + Send + Clone + 'static,
+    CIDP: CreateInherentDataProviders<Block, (PHash, PersistedValidationData)>
+        + Send
+        + 'static
+        + Clone,
+    CIDP::InherentDataProviders: Send + InherentDataProviderExt,
+    BI: BlockImport<Block> + ParachainBlockImportMarker + Send + Sync + 'static,
+    SO: SyncOracle + Send + Sync + Clone + 'static,
+    Proposer: ProposerInterface<Block> + Send + Sync + 'static,
+    CS: CollatorServiceInterface<Block> + Send + Sync + 'static,
+    AI: CollatorAuthorityId,
+    <AI::Pair as Pair>::Public: AppPublic + Member + Codec,
+    <AI::Pair as Pair>::Signature: TryFrom<Vec<u8>> + Member + Codec,
+    GOH: RetrieveAuthoritiesFromOrchestrator<
+            Block,
+            (PHash, PersistedValidationData),
+            Vec<AuthorityId<AI::Pair>>,
+        >
+        + 'static
+        + Sync
+        + Send,
+    SRP: Fn() -> SRPFut + Send + 'static,
+    SRPFut: Future<Output = Option<PHash>> + Send + 'static,
+{
+    async move {
+        let (collation_sender, mut collation_receiver) = futures::channel::mpsc::channel(8);
+
+        let mut overseer_handle = params.overseer_handle.clone();
+
+        let collation_submission_task = async move {
+            while let Some(built) = collation_receiver.next().await {
+                let BuiltCollation {
+                    relay_parent,
+                    parent_header,
+                    validation_code_hash,
+                    result,
+                } = built;
+
+                overseer_handle
+                    .send_msg(
+                        CollationGenerationMessage::SubmitCollation(SubmitCollationParams {
+                            relay_parent,
+                            collation: result.collation,
+                            parent_head: parent_header.encode().into(),
+                            validation_code_hash,
+                            result_sender: result.result_sender,
+                        }),
+                        "SlotBasedCollator",
+                    )
+                    .await;
+            }
+        };
+
+        let block_building_task =
+            block_building_task::<Block, AI, _, _, _, _, _, _, _, _, _, _>(params, collation_sender);
+
+        futures::future::join(block_building_task, collation_submission_task)
+            .map(|_| ())
+            .await
+    }
+}
+
+/// The block-building half of [`run_slot_based`]: wakes on each parachain
+/// slot boundary, searches for a potential parent, claims the slot and
+/// authors a block, then hands the resulting collation off to
+/// `collation_sender`.
+async fn block_building_task<Block, AI, BI, CIDP, Client, RClient, SO, Proposer, CS, GOH, SRP, SRPFut>(
+    params: SlotBasedParams<BI, CIDP, Client, RClient, SO, Proposer, CS, GOH, SRP>,
+    mut collation_sender: Sender<BuiltCollation<Block>>,
+) where
+    Block: BlockT + Send,
+    Client: ProvideRuntimeApi<Block>
+        + BlockOf
+        + AuxStore
+        + HeaderBackend<Block>
+        + BlockBackend<Block>
+        + Send
+        + Sync
+        + 'static,
+    RClient: RelayChainInterface + Send + Clone + 'static,
+    CIDP: CreateInherentDataProviders<Block, (PHash, PersistedValidationData)>
+        + Send
+        + 'static
+        + Clone,
+    CIDP::InherentDataProviders: Send + InherentDataProviderExt,
+    BI: BlockImport<Block> + ParachainBlockImportMarker + Send + Sync + 'static,
+    SO: SyncOracle + Send + Sync + Clone + 'static,
+    Proposer: ProposerInterface<Block> + Send + Sync + 'static,
+    CS: CollatorServiceInterface<Block> + Send + Sync + 'static,
+    AI: CollatorAuthorityId,
+    <AI::Pair as Pair>::Public: AppPublic + Member + Codec,
+    <AI::Pair as Pair>::Signature: TryFrom<Vec<u8>> + Member + Codec,
+    GOH: RetrieveAuthoritiesFromOrchestrator<
+            Block,
+            (PHash, PersistedValidationData),
+            Vec<AuthorityId<AI::Pair>>,
+        >
+        + 'static
+        + Sync
+        + Send,
+    SRP: Fn() -> SRPFut + Send + 'static,
+    SRPFut: Future<Output = Option<PHash>> + Send + 'static,
+{
+    let slot_duration = params.slot_duration;
+    let metrics = params.metrics.clone();
+
+    let mut collator = {
+        let collator_params = collator_util::Params {
+            create_inherent_data_providers: params.create_inherent_data_providers.clone(),
+            block_import: params.block_import,
+            relay_client: params.relay_client.clone(),
+            keystore: params.keystore.clone(),
+            para_id: params.para_id,
+            proposer: params.proposer,
+            collator_service: params.collator_service,
+        };
+
+        collator_util::Collator::<Block, AI::Pair, _, _, _, _, _>::new(collator_params)
+    };
+
+    loop {
+        // Wake at the next absolute slot boundary rather than sleeping a
+        // fixed relative duration, so that RPC and authoring time spent in
+        // the loop body each iteration doesn't accumulate as drift.
+        Delay::new(time_until_next_slot(slot_duration.as_duration())).await;
+
+        let relay_parent = match (params.select_relay_parent)().await {
+            Some(rp) => rp,
+            None => continue,
+        };
+
+        if let Some(metrics) = &metrics {
+            metrics.on_collation_requested();
+        }
+
+        let parent = match find_potential_parent::<Block, _>(
+            &params.relay_client,
+            relay_parent,
+            params.para_id,
+            params.ancestry_lookback,
+            params.max_depth,
+        )
+        .await
+        {
+            Ok(Some(parent)) => parent,
+            Ok(None) => continue,
+            Err(e) => {
+                tracing::error!(target: crate::LOG_TARGET, err = ?e, "potential parent search failed");
+                continue;
+            }
+        };
+
+        let parent_depth = parent.depth;
+        let parent_header = parent.header;
+        let parent_hash = parent_header.hash();
+
+        if !collator
+            .collator_service()
+            .check_block_status(parent_hash, &parent_header)
+        {
+            if let Some(metrics) = &metrics {
+                metrics.on_collation_rejected(RejectReason::BadBlockStatus);
+            }
+            continue;
+        }
+
+        // Fetch the real PVD for the included block at this relay parent, as
+        // `find_potential_parent` does, then override `parent_head` with the
+        // potential parent we've chosen to build on. This keeps
+        // `max_pov_size` and `relay_parent_storage_root` accurate instead of
+        // fabricating them.
+        let validation_data = match params
+            .relay_client
+            .persisted_validation_data(relay_parent, params.para_id, Default::default())
+            .await
+        {
+            Ok(Some(mut pvd)) => {
+                pvd.parent_head = parent_header.encode().into();
+                pvd
+            }
+            Ok(None) => continue,
+            Err(e) => {
+                tracing::error!(target: crate::LOG_TARGET, err = ?e, "failed to fetch persisted validation data");
+                continue;
+            }
+        };
+
+        let authorities = match params
+            .get_authorities_from_orchestrator
+            .retrieve_authorities_from_orchestrator(
+                parent_hash,
+                (relay_parent, validation_data.clone()),
+            )
+            .await
+        {
+            Ok(h) => h,
+            Err(e) => {
+                tracing::error!(target: crate::LOG_TARGET, err = ?e, "failed to retrieve authorities");
+                continue;
+            }
+        };
+
+        let inherent_providers = match params
+            .create_inherent_data_providers
+            .create_inherent_data_providers(parent_hash, (relay_parent, validation_data.clone()))
+            .await
+        {
+            Ok(h) => h,
+            Err(e) => {
+                tracing::error!(target: crate::LOG_TARGET, err = ?e, "failed to create inherent data providers");
+                continue;
+            }
+        };
+
+        let mut claim = match collator_util::tanssi_claim_slot::<AI::Pair>(
+            authorities.clone(),
+            inherent_providers.slot(),
+            params.force_authoring,
+            &params.keystore,
+        ) {
+            Ok(Some(claim)) => claim,
+            Ok(None) => {
+                if let Some(metrics) = &metrics {
+                    metrics.on_collation_rejected(RejectReason::NotOurSlot);
+                }
+                continue;
+            }
+            Err(e) => {
+                tracing::error!(target: crate::LOG_TARGET, err = ?e, "failed to claim slot");
+                continue;
+            }
+        };
+
+        // Default to 50% of the maximum PoV size until the runtime's
+        // weights account for proof size; operators can raise this via
+        // `SlotBasedParams::block_size_limit` once they have
+        // proof-size-aware weights.
+        let block_size_limit = params
+            .block_size_limit
+            .unwrap_or((validation_data.max_pov_size / 2) as usize);
+        // `parent.depth` already accounts for `params.max_depth`, so cap the
+        // chain length here to avoid authoring blocks that would push the
+        // unincluded segment past the relay chain's async backing limit.
+        let max_blocks_per_request = params
+            .max_blocks_per_request
+            .unwrap_or(1)
+            .max(1)
+            .min(params.max_depth.saturating_sub(parent_depth).max(1));
+
+        let mut building_parent_header = parent_header;
+        let mut building_parent_hash = parent_hash;
+        let mut next_slot = inherent_providers.slot();
+        let mut remaining_pov_size = block_size_limit;
+        let mut remaining_authoring_duration = params.authoring_duration;
+
+        let mut last_collation = None;
+        let mut last_parent_header = building_parent_header.clone();
+        let mut last_post_hash = building_parent_hash;
+
+        for block_index in 0..max_blocks_per_request {
+            let (parachain_inherent_data, other_inherent_data) = match collator
+                .create_inherent_data(relay_parent, &validation_data, building_parent_hash, None)
+                .await
+            {
+                Ok(data) => data,
+                Err(e) => {
+                    tracing::error!(target: crate::LOG_TARGET, err = ?e, "failed to create inherent data for additional block in unincluded segment");
+                    break;
+                }
+            };
+
+            let authoring_started = Instant::now();
+
+            let (collation, _, post_hash) = match collator
+                .collate(
+                    &building_parent_header,
+                    &mut claim,
+                    None,
+                    (parachain_inherent_data, other_inherent_data),
+                    remaining_authoring_duration,
+                    remaining_pov_size,
+                )
+                .await
+            {
+                Ok(result) => result,
+                Err(e) => {
+                    // Only count this as a rejected collation if we failed to
+                    // produce even the first block; later failures just
+                    // truncate an otherwise-successful chained slot, which is
+                    // still submitted below.
+                    if block_index == 0 {
+                        if let Some(metrics) = &metrics {
+                            metrics.on_collation_rejected(RejectReason::ProposerError);
+                        }
+                    }
+                    tracing::error!(target: crate::LOG_TARGET, err = ?e, "failed to author block");
+                    break;
+                }
+            };
+
+            let authoring_elapsed = authoring_started.elapsed();
+            let pov_size = collation.proof_of_validity.encode().len();
+
+            if let Some(metrics) = &metrics {
+                metrics.on_collation_produced(
+                    authoring_elapsed.as_secs_f64(),
+                    pov_size as f64 / validation_data.max_pov_size as f64,
+                );
+            }
+
+            last_collation = Some(collation);
+            last_parent_header = building_parent_header.clone();
+            last_post_hash = post_hash;
+
+            let more_blocks_wanted = block_index + 1 < max_blocks_per_request;
+            if !more_blocks_wanted {
+                break;
+            }
+
+            remaining_pov_size = remaining_pov_size.saturating_sub(pov_size);
+            remaining_authoring_duration =
+                remaining_authoring_duration.saturating_sub(authoring_elapsed);
+            if remaining_pov_size == 0 || remaining_authoring_duration.is_zero() {
+                break;
+            }
+
+            next_slot = (*next_slot + 1).into();
+            claim = match collator_util::tanssi_claim_slot::<AI::Pair>(
+                authorities.clone(),
+                next_slot,
+                params.force_authoring,
+                &params.keystore,
+            ) {
+                Ok(Some(claim)) => claim,
+                // We no longer hold consecutive slots, or failed to claim
+                // the next one: stop chaining and submit what we have so
+                // far.
+                Ok(None) => break,
+                Err(e) => {
+                    tracing::error!(target: crate::LOG_TARGET, err = ?e, "failed to claim consecutive slot");
+                    break;
+                }
+            };
+
+            building_parent_header = match Block::Header::decode(
+                &mut &last_collation.as_ref().expect("set above; qed").head_data.0[..],
+            ) {
+                Ok(h) => h,
+                Err(e) => {
+                    tracing::error!(target: crate::LOG_TARGET, err = ?e, "failed to decode authored header");
+                    break;
+                }
+            };
+            building_parent_hash = post_hash;
+        }
+
+        let collation = match last_collation {
+            Some(collation) => collation,
+            None => continue,
+        };
+
+        let validation_code_hash = match params
+            .relay_client
+            .validation_code_hash(relay_parent, params.para_id, Default::default())
+            .await
+        {
+            Ok(Some(hash)) => hash,
+            Ok(None) => continue,
+            Err(e) => {
+                tracing::error!(target: crate::LOG_TARGET, err = ?e, "failed to fetch validation code hash");
+                continue;
+            }
+        };
+
+        let result_sender = Some(
+            collator
+                .collator_service()
+                .announce_with_barrier(last_post_hash),
+        );
+        let _ = collation_sender
+            .send(BuiltCollation {
+                relay_parent,
+                parent_header: last_parent_header,
+                validation_code_hash,
+                result: CollationResult {
+                    collation,
+                    result_sender,
+                },
+            })
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sp_core::H256;
+    use sp_runtime::testing::{Block as TestBlock, ExtrinsicWrapper, Header as TestHeader};
+
+    type Block = TestBlock<ExtrinsicWrapper<u64>>;
+
+    fn header(number: u64, parent_hash: H256) -> TestHeader {
+        TestHeader::new(
+            number,
+            Default::default(),
+            Default::default(),
+            parent_hash,
+            Default::default(),
+        )
+    }
+
+    #[test]
+    fn picks_deepest_connected_chain_over_unconnected_fork() {
+        let included = header(10, H256::zero());
+        let included_hash = included.hash();
+
+        // `a` chains directly off the included block, and `b` chains off
+        // `a`. `c` is discovered at the same relay block as `b` but has an
+        // unrelated parent hash, so it must never be selected even though
+        // it would tie `b`'s naive BFS hop count.
+        let a = header(11, included_hash);
+        let a_hash = a.hash();
+        let b = header(12, a_hash);
+        let c = header(12, H256::repeat_byte(0xaa));
+
+        let mut candidates = HashMap::new();
+        candidates.insert(a_hash, (a, 100));
+        candidates.insert(b.hash(), (b.clone(), 101));
+        candidates.insert(c.hash(), (c, 101));
+
+        let best = resolve_potential_parent::<Block>(included, 100, candidates, 10);
+
+        assert_eq!(best.header, b);
+        assert_eq!(best.depth, 2);
+    }
+
+    #[test]
+    fn falls_back_to_included_block_when_nothing_connects() {
+        let included = header(10, H256::zero());
+
+        let orphan = header(11, H256::repeat_byte(0xaa));
+        let mut candidates = HashMap::new();
+        candidates.insert(orphan.hash(), (orphan, 101));
+
+        let best = resolve_potential_parent::<Block>(included.clone(), 100, candidates, 10);
+
+        assert_eq!(best.header, included);
+        assert_eq!(best.depth, 0);
+    }
+
+    #[test]
+    fn excludes_candidates_at_or_beyond_max_depth() {
+        let included = header(10, H256::zero());
+        let included_hash = included.hash();
+        let a = header(11, included_hash);
+
+        let mut candidates = HashMap::new();
+        candidates.insert(a.hash(), (a, 101));
+
+        // `max_depth == 1` means a node at depth 1 fails `depth < max_depth`
+        // and must not be selected.
+        let best = resolve_potential_parent::<Block>(included.clone(), 100, candidates, 1);
+
+        assert_eq!(best.header, included);
+        assert_eq!(best.depth, 0);
+    }
+}
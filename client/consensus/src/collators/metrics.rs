@@ -0,0 +1,127 @@
+// Copyright (C) Moondance Labs Ltd.
+// This file is part of Tanssi.
+
+// Tanssi is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Tanssi is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Tanssi.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Prometheus metrics for the collation loops in [`super::basic`] and
+//! [`super::lookahead`].
+
+use substrate_prometheus_endpoint::{
+    register, Counter, CounterVec, Histogram, HistogramOpts, Opts, PrometheusError, Registry, U64,
+};
+
+/// The reason a collation request was rejected, used to label
+/// [`Metrics::collations_rejected`].
+pub enum RejectReason {
+    /// We were not part of the authority set for this slot.
+    NotOurSlot,
+    /// The candidate block's parent failed the block status check.
+    BadBlockStatus,
+    /// The proposer failed to author a block.
+    ProposerError,
+}
+
+impl RejectReason {
+    fn as_label(&self) -> &'static str {
+        match self {
+            RejectReason::NotOurSlot => "not_our_slot",
+            RejectReason::BadBlockStatus => "bad_block_status",
+            RejectReason::ProposerError => "proposer_error",
+        }
+    }
+}
+
+/// Prometheus metrics for the collation loop.
+///
+/// These let operators distinguish a collator that is idle because it is not
+/// part of the current authority set from one that is failing to author
+/// blocks.
+#[derive(Clone)]
+pub struct Metrics {
+    collation_requests_received: Counter<U64>,
+    collations_produced: Counter<U64>,
+    collations_rejected: CounterVec<U64>,
+    authoring_duration: Histogram,
+    pov_size_ratio: Histogram,
+}
+
+impl Metrics {
+    /// Register the collator metrics with the given Prometheus registry.
+    pub fn register(registry: &Registry) -> Result<Self, PrometheusError> {
+        Ok(Self {
+            collation_requests_received: register(
+                Counter::new(
+                    "tanssi_collator_collation_requests_received_total",
+                    "Number of collation requests received from the relay chain, or slot ticks with a relay parent in the slot-based collator",
+                )?,
+                registry,
+            )?,
+            collations_produced: register(
+                Counter::new(
+                    "tanssi_collator_collations_produced_total",
+                    "Number of collations successfully produced",
+                )?,
+                registry,
+            )?,
+            collations_rejected: register(
+                CounterVec::new(
+                    Opts::new(
+                        "tanssi_collator_collations_rejected_total",
+                        "Number of collation requests rejected, by reason",
+                    ),
+                    &["reason"],
+                )?,
+                registry,
+            )?,
+            authoring_duration: register(
+                Histogram::with_opts(HistogramOpts::new(
+                    "tanssi_collator_authoring_duration_seconds",
+                    "Time taken to propose and author a collation",
+                ))?,
+                registry,
+            )?,
+            pov_size_ratio: register(
+                Histogram::with_opts(
+                    HistogramOpts::new(
+                        "tanssi_collator_pov_size_ratio",
+                        "Ratio of produced PoV size to the max PoV size allowed by the relay chain",
+                    )
+                    .buckets(vec![0.1, 0.25, 0.5, 0.75, 0.9, 1.0]),
+                )?,
+                registry,
+            )?,
+        })
+    }
+
+    /// Record a collation request being received (in the slot-based
+    /// collator: a slot tick for which a relay parent was selected).
+    pub fn on_collation_requested(&self) {
+        self.collation_requests_received.inc();
+    }
+
+    /// Record a collation being rejected, labelled by `reason`.
+    pub fn on_collation_rejected(&self, reason: RejectReason) {
+        self.collations_rejected
+            .with_label_values(&[reason.as_label()])
+            .inc();
+    }
+
+    /// Record a collation being produced, along with how long authoring took
+    /// and the resulting PoV size relative to `max_pov_size`.
+    pub fn on_collation_produced(&self, authoring_duration_seconds: f64, pov_size_ratio: f64) {
+        self.collations_produced.inc();
+        self.authoring_duration.observe(authoring_duration_seconds);
+        self.pov_size_ratio.observe(pov_size_ratio);
+    }
+}